@@ -6,9 +6,17 @@ use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     Sample, SupportedStreamConfig,
 };
-use crossterm::event::{read, Event, KeyCode, KeyEvent};
-use crossterm::terminal::enable_raw_mode;
-use std::collections::HashMap;
+use crossterm::event::{
+    read, Event, KeyCode, KeyEvent, KeyEventKind, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::terminal::{enable_raw_mode, supports_keyboard_enhancement};
+use crossterm::execute;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::cell::UnsafeCell;
+use std::collections::{HashMap, HashSet};
+use std::io::stdout;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 lazy_static! {
@@ -65,7 +73,569 @@ fn note_to_frequency(note: &str, octave: i32) -> f64 {
     midi_to_freq(midi_note_number)
 }
 
-const RELEASE_TIME_SECONDS: f32 = 3.0;
+const MAX_VOICES: usize = 16;
+
+/// Attack/decay/sustain/release knobs, all in seconds except `sustain`
+/// which is a level in `0.0..=1.0`.
+#[derive(Clone, Copy)]
+struct Adsr {
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr {
+            attack: 0.05,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.3,
+        }
+    }
+}
+
+/// Stage of a voice's envelope. Advances forward only, except `Release`
+/// which can be entered from any earlier stage on key-up.
+#[derive(Clone, Copy, PartialEq)]
+enum EnvStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+/// Per-voice envelope generator, advanced once per sample.
+struct EnvState {
+    stage: EnvStage,
+    level: f32,
+}
+
+impl EnvState {
+    fn new() -> Self {
+        EnvState {
+            stage: EnvStage::Attack,
+            level: 0.0,
+        }
+    }
+
+    fn release(&mut self) {
+        if self.stage != EnvStage::Off {
+            self.stage = EnvStage::Release;
+        }
+    }
+
+    fn advance(&mut self, adsr: &Adsr, sample_rate: f32) -> f32 {
+        match self.stage {
+            EnvStage::Attack => {
+                self.level += 1.0 / (adsr.attack * sample_rate).max(1.0);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = EnvStage::Decay;
+                }
+            }
+            EnvStage::Decay => {
+                self.level -= (1.0 - adsr.sustain) / (adsr.decay * sample_rate).max(1.0);
+                if self.level <= adsr.sustain {
+                    self.level = adsr.sustain;
+                    self.stage = EnvStage::Sustain;
+                }
+            }
+            EnvStage::Sustain => {
+                self.level = adsr.sustain;
+            }
+            EnvStage::Release => {
+                self.level -= adsr.sustain / (adsr.release * sample_rate).max(1.0);
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = EnvStage::Off;
+                }
+            }
+            EnvStage::Off => {
+                self.level = 0.0;
+            }
+        }
+        self.level
+    }
+}
+
+/// Identifies what triggered a voice, so a note-off from either input
+/// source only releases the voice it actually started.
+#[derive(Clone, Copy, PartialEq)]
+enum VoiceId {
+    Key(char),
+    Midi(u8),
+}
+
+/// Number of partials summed in additive-synthesis mode.
+const MAX_PARTIALS: usize = 5;
+
+/// A single playing note. Each voice owns its phase accumulator(s) and
+/// envelope state so overlapping notes no longer fight over one shared
+/// frequency.
+struct Voice {
+    id: VoiceId,
+    frequency: f32,
+    velocity: f32,
+    phase: f32,
+    partial_phase: [f32; MAX_PARTIALS],
+    env: EnvState,
+}
+
+impl Voice {
+    fn new(id: VoiceId, frequency: f32, velocity: f32) -> Self {
+        Voice {
+            id,
+            frequency,
+            velocity,
+            phase: 0.0,
+            partial_phase: [0.0; MAX_PARTIALS],
+            env: EnvState::new(),
+        }
+    }
+}
+
+/// A basic oscillator shape, computed from a `0.0..1.0` running phase.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Waveform {
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+}
+
+impl Waveform {
+    fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Sine => (phase * 2.0 * std::f32::consts::PI).sin(),
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+/// Which fixed set of partial multipliers an additive voice sums.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PartialSet {
+    /// Integer harmonics: a brighter, "organ-like" timbre.
+    Harmonic,
+    /// Detuned, non-integer ratios for an inharmonic, bell-like timbre.
+    Inharmonic,
+}
+
+const HARMONIC_PARTIALS: [f32; MAX_PARTIALS] = [1.0, 2.0, 3.0, 4.0, 5.0];
+const INHARMONIC_PARTIALS: [f32; MAX_PARTIALS] = [0.8, 1.0, 1.2, 1.7, 2.9];
+
+impl PartialSet {
+    fn multipliers(self) -> &'static [f32; MAX_PARTIALS] {
+        match self {
+            PartialSet::Harmonic => &HARMONIC_PARTIALS,
+            PartialSet::Inharmonic => &INHARMONIC_PARTIALS,
+        }
+    }
+}
+
+/// The synth's current oscillator: either a single waveform, or additive
+/// synthesis summing several detuned/harmonic partials per voice.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum OscillatorMode {
+    Waveform(Waveform),
+    Additive(PartialSet),
+}
+
+impl OscillatorMode {
+    /// Cycles through Sine -> Square -> Saw -> Triangle -> Harmonic additive
+    /// -> Inharmonic additive -> Sine, for the live waveform-switch key.
+    fn next(self) -> Self {
+        match self {
+            OscillatorMode::Waveform(Waveform::Sine) => OscillatorMode::Waveform(Waveform::Square),
+            OscillatorMode::Waveform(Waveform::Square) => OscillatorMode::Waveform(Waveform::Saw),
+            OscillatorMode::Waveform(Waveform::Saw) => OscillatorMode::Waveform(Waveform::Triangle),
+            OscillatorMode::Waveform(Waveform::Triangle) => {
+                OscillatorMode::Additive(PartialSet::Harmonic)
+            }
+            OscillatorMode::Additive(PartialSet::Harmonic) => {
+                OscillatorMode::Additive(PartialSet::Inharmonic)
+            }
+            OscillatorMode::Additive(PartialSet::Inharmonic) => {
+                OscillatorMode::Waveform(Waveform::Sine)
+            }
+        }
+    }
+}
+
+/// Sums `partials` worth of detuned sine partials at `1/k` decaying
+/// amplitude weight, each with its own phase accumulator, and normalizes
+/// by the total weight so the result stays roughly in `-1.0..=1.0`.
+fn additive_sample(
+    partial_phase: &mut [f32; MAX_PARTIALS],
+    base_frequency: f32,
+    sample_rate: f32,
+    partials: &[f32; MAX_PARTIALS],
+) -> f32 {
+    let mut sum = 0.0;
+    let mut weight_total = 0.0;
+    for (k, &multiplier) in partials.iter().enumerate() {
+        partial_phase[k] = (partial_phase[k] + base_frequency * multiplier / sample_rate).fract();
+        let weight = 1.0 / (k as f32 + 1.0);
+        sum += (partial_phase[k] * 2.0 * std::f32::consts::PI).sin() * weight;
+        weight_total += weight;
+    }
+    sum / weight_total
+}
+
+/// Single-producer/single-consumer lock-free circular buffer, modeled on
+/// moa's `CircularBuffer`: `insert` only advances `inp` when the slot
+/// after it isn't `out` (buffer full, event dropped), and `read` only
+/// advances `out` when there's something queued.
+///
+/// This is genuinely SPSC, not MPSC: `insert` reads-then-writes `inp`
+/// without any synchronization between producers, so two threads calling
+/// `insert` concurrently can race on the same slot. The terminal thread
+/// and the MIDI thread each get their own `CircularBuffer` (see
+/// `Synth::key_events` / `Synth::midi_events`) rather than sharing one,
+/// so every instance still has exactly one producer.
+struct CircularBuffer<T> {
+    buffer: UnsafeCell<Vec<Option<T>>>,
+    inp: AtomicUsize,
+    out: AtomicUsize,
+}
+
+unsafe impl<T: Send> Sync for CircularBuffer<T> {}
+
+impl<T> CircularBuffer<T> {
+    fn new(capacity: usize) -> Self {
+        let mut buffer = Vec::with_capacity(capacity);
+        buffer.resize_with(capacity, || None);
+        CircularBuffer {
+            buffer: UnsafeCell::new(buffer),
+            inp: AtomicUsize::new(0),
+            out: AtomicUsize::new(0),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        unsafe { (*self.buffer.get()).len() }
+    }
+
+    fn next_in(&self, inp: usize) -> usize {
+        (inp + 1) % self.capacity()
+    }
+
+    /// Producer side: pushes an event, or silently drops it (returning
+    /// `false`) if the buffer is full.
+    fn insert(&self, item: T) -> bool {
+        let inp = self.inp.load(Ordering::Relaxed);
+        let next = self.next_in(inp);
+        if next == self.out.load(Ordering::Acquire) {
+            return false;
+        }
+        // Safety: only the producer ever writes to `buffer[inp]`, and it
+        // only does so before publishing `inp` via the release store below.
+        unsafe {
+            (*self.buffer.get()).as_mut_slice()[inp] = Some(item);
+        }
+        self.inp.store(next, Ordering::Release);
+        true
+    }
+
+    /// Consumer side: pops the oldest queued event, if any.
+    fn read(&self) -> Option<T> {
+        let out = self.out.load(Ordering::Relaxed);
+        if out == self.inp.load(Ordering::Acquire) {
+            return None;
+        }
+        // Safety: only the consumer ever writes to `buffer[out]`, and it
+        // only does so before publishing `out` via the release store below.
+        let item = unsafe { (*self.buffer.get()).as_mut_slice()[out].take() };
+        self.out.store((out + 1) % self.capacity(), Ordering::Release);
+        item
+    }
+}
+
+/// Control-plane events the terminal thread and the MIDI thread each push
+/// into their own ring buffer; the audio callback drains and applies them at the top
+/// of every buffer fill instead of locking per sample.
+#[derive(Clone, Copy)]
+enum ControlEvent {
+    NoteOn {
+        id: VoiceId,
+        frequency: f32,
+        velocity: f32,
+    },
+    NoteOff {
+        id: VoiceId,
+    },
+    PitchBend(f32), // offset in semitones
+    SetOscillator(OscillatorMode),
+    SetAdsr(Adsr),
+}
+
+/// Parse a raw MIDI message (status byte + up to two data bytes) into the
+/// control events our voice engine understands. Note-On with velocity 0
+/// is the running-status convention for Note-Off.
+fn parse_midi_message(message: &[u8]) -> Option<ControlEvent> {
+    let status = *message.first()? & 0xF0;
+    match status {
+        0x90 => {
+            let note = *message.get(1)?;
+            let velocity = *message.get(2)?;
+            if velocity == 0 {
+                Some(ControlEvent::NoteOff {
+                    id: VoiceId::Midi(note),
+                })
+            } else {
+                Some(ControlEvent::NoteOn {
+                    id: VoiceId::Midi(note),
+                    frequency: midi_to_freq(note as i32) as f32,
+                    velocity: velocity as f32 / 127.0,
+                })
+            }
+        }
+        0x80 => Some(ControlEvent::NoteOff {
+            id: VoiceId::Midi(*message.get(1)?),
+        }),
+        0xE0 => {
+            let lsb = *message.get(1)? as u16;
+            let msb = *message.get(2)? as u16;
+            let value = lsb | (msb << 7);
+            let cents = (value as f32 - 8192.0) / 8192.0 * 200.0; // +/- 2 semitones
+            Some(ControlEvent::PitchBend(cents / 100.0))
+        }
+        _ => None,
+    }
+}
+
+/// Opens the first available MIDI input device, if any, and feeds its
+/// Note-On/Note-Off/pitch-bend messages into its own event ring — the
+/// MIDI callback thread is this ring's sole producer. The returned
+/// connection must be kept alive for as long as MIDI input is wanted.
+fn start_midi_input(
+    events: Arc<CircularBuffer<ControlEvent>>,
+) -> Result<Option<MidiInputConnection<()>>> {
+    let mut midi_in = MidiInput::new("my_synth")?;
+    midi_in.ignore(Ignore::None);
+
+    let ports = midi_in.ports();
+    let port = match ports.first() {
+        Some(port) => port.clone(),
+        None => {
+            eprintln!("no MIDI input devices found, using keyboard only\r");
+            return Ok(None);
+        }
+    };
+    let port_name = midi_in.port_name(&port)?;
+
+    let connection = midi_in
+        .connect(
+            &port,
+            "my_synth-input",
+            move |_stamp, message, _| {
+                if let Some(event) = parse_midi_message(message) {
+                    events.insert(event);
+                }
+            },
+            (),
+        )
+        .map_err(|err| anyhow::anyhow!("failed to connect to MIDI port: {err}"))?;
+
+    eprintln!("listening for MIDI input on '{port_name}'\r");
+    Ok(Some(connection))
+}
+
+/// Captures raw output samples while recording is toggled on, mirroring
+/// progmidi's `WavRecording`: samples are stored as clamped 16-bit PCM so
+/// writing the file out needs no further conversion, only the header plus
+/// `data` in little-endian order.
+struct WavRecording {
+    data: Vec<i16>,
+}
+
+impl WavRecording {
+    fn new() -> Self {
+        WavRecording { data: Vec::new() }
+    }
+
+    fn push(&mut self, sample: f32) {
+        let clamped = sample.clamp(-1.0, 1.0);
+        self.data.push((clamped * i16::MAX as f32) as i16);
+    }
+}
+
+/// Writes `recording` out as a canonical 16-bit PCM WAV file, with the
+/// `fmt` chunk built from the stream's actual sample rate and channel
+/// count and the `data` chunk length set from the captured sample count.
+fn write_wav_file(
+    path: &str,
+    recording: &WavRecording,
+    sample_rate: u32,
+    channels: u16,
+) -> Result<()> {
+    use std::io::Write;
+
+    let bits_per_sample: u16 = 16;
+    let byte_rate = sample_rate * channels as u32 * (bits_per_sample as u32 / 8);
+    let block_align = channels * (bits_per_sample / 8);
+    let data_len = (recording.data.len() * 2) as u32;
+    let riff_len = 36 + data_len;
+
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(b"RIFF")?;
+    file.write_all(&riff_len.to_le_bytes())?;
+    file.write_all(b"WAVE")?;
+    file.write_all(b"fmt ")?;
+    file.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+    file.write_all(&1u16.to_le_bytes())?; // PCM
+    file.write_all(&channels.to_le_bytes())?;
+    file.write_all(&sample_rate.to_le_bytes())?;
+    file.write_all(&byte_rate.to_le_bytes())?;
+    file.write_all(&block_align.to_le_bytes())?;
+    file.write_all(&bits_per_sample.to_le_bytes())?;
+    file.write_all(b"data")?;
+    file.write_all(&data_len.to_le_bytes())?;
+    for sample in &recording.data {
+        file.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn default_wav_path() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("recording-{secs}.wav")
+}
+
+/// The audio callback's entire state, owned outright by the audio thread.
+/// The control-plane fields (voices, adsr, pitch_bend, oscillator) used to
+/// live behind a per-sample `Mutex`; they're now mutated only by
+/// [`Synth::drain_events`], which runs once per buffer fill and never
+/// locks. `recording` is the exception: [`Synth::next_sample`] still locks
+/// it on every sample to append to the in-progress WAV capture, same as
+/// when it was introduced — that path isn't part of this ring-buffer
+/// hand-off and remains a plain `Mutex`.
+///
+/// There are two event rings, one per producer thread, because
+/// `CircularBuffer` is only sound with a single producer: sharing one
+/// ring between the terminal thread and the MIDI thread would let both
+/// race on the same `inp` slot.
+struct Synth {
+    sample_rate: f32,
+    channels: usize,
+    target_amplitude: f32,
+    voices: Vec<Voice>,
+    adsr: Adsr,
+    pitch_bend: f32,
+    oscillator: OscillatorMode,
+    recording: Arc<Mutex<Option<WavRecording>>>,
+    key_events: Arc<CircularBuffer<ControlEvent>>,
+    midi_events: Arc<CircularBuffer<ControlEvent>>,
+}
+
+impl Synth {
+    fn new(
+        sample_rate: f32,
+        channels: usize,
+        recording: Arc<Mutex<Option<WavRecording>>>,
+        key_events: Arc<CircularBuffer<ControlEvent>>,
+        midi_events: Arc<CircularBuffer<ControlEvent>>,
+    ) -> Self {
+        Synth {
+            sample_rate,
+            channels,
+            target_amplitude: 0.5,
+            voices: Vec::with_capacity(MAX_VOICES),
+            adsr: Adsr::default(),
+            pitch_bend: 0.0,
+            oscillator: OscillatorMode::Waveform(Waveform::Sine),
+            recording,
+            key_events,
+            midi_events,
+        }
+    }
+
+    fn drain_events(&mut self) {
+        while let Some(event) = self.key_events.read() {
+            self.apply_event(event);
+        }
+        while let Some(event) = self.midi_events.read() {
+            self.apply_event(event);
+        }
+    }
+
+    fn apply_event(&mut self, event: ControlEvent) {
+        match event {
+            ControlEvent::NoteOn {
+                id,
+                frequency,
+                velocity,
+            } => {
+                if self.voices.len() >= MAX_VOICES {
+                    self.voices.remove(0);
+                }
+                self.voices.push(Voice::new(id, frequency, velocity));
+            }
+            ControlEvent::NoteOff { id } => {
+                for voice in self.voices.iter_mut() {
+                    if voice.id == id {
+                        voice.env.release();
+                    }
+                }
+            }
+            ControlEvent::PitchBend(semitones) => self.pitch_bend = semitones,
+            ControlEvent::SetOscillator(mode) => self.oscillator = mode,
+            ControlEvent::SetAdsr(adsr) => self.adsr = adsr,
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let bend_ratio = 2.0f32.powf(self.pitch_bend / 12.0);
+
+        let mut mixed = 0.0f32;
+        for voice in self.voices.iter_mut() {
+            let level = voice.env.advance(&self.adsr, self.sample_rate) * voice.velocity;
+            let frequency = voice.frequency * bend_ratio;
+            let raw = match self.oscillator {
+                OscillatorMode::Waveform(wave) => {
+                    voice.phase = (voice.phase + frequency / self.sample_rate).fract();
+                    wave.sample(voice.phase)
+                }
+                OscillatorMode::Additive(set) => additive_sample(
+                    &mut voice.partial_phase,
+                    frequency,
+                    self.sample_rate,
+                    set.multipliers(),
+                ),
+            };
+            mixed += raw * level;
+        }
+
+        // Soft normalization so a handful of simultaneous notes don't clip.
+        let norm = (self.voices.len().max(1) as f32).sqrt();
+
+        self.voices.retain(|voice| voice.env.stage != EnvStage::Off);
+
+        let sample = mixed * self.target_amplitude / norm;
+        if let Some(recording) = self.recording.lock().unwrap().as_mut() {
+            // `write_data` copies this sample to every channel of the frame,
+            // so record it once per channel to match the WAV header.
+            for _ in 0..self.channels {
+                recording.push(sample);
+            }
+        }
+        sample
+    }
+}
 
 fn main() -> Result<()> {
     // Enable raw mode
@@ -89,6 +659,8 @@ fn main() -> Result<()> {
 
     match config.sample_format() {
         cpal::SampleFormat::F32 => run::<f32>(&device, &def_config.into())?,
+        cpal::SampleFormat::I16 => run::<i16>(&device, &def_config.into())?,
+        cpal::SampleFormat::U16 => run::<u16>(&device, &def_config.into())?,
         sample_format => panic!("Unsupported sample format '{:?}'", sample_format),
     }
 
@@ -103,54 +675,73 @@ where
     let sample_rate = config.sample_rate.0 as f32;
     let channels = config.channels as usize;
 
-    let mut sample_clock = 0f32;
-    let frequency = Arc::new(Mutex::new(0.0f32)); // Will hold the frequency
-    let frequency_clone = frequency.clone();
+    // One ring per producer thread: `CircularBuffer` is only sound with a
+    // single producer, so the terminal loop and the MIDI callback each
+    // get their own rather than racing on a shared one.
+    let key_events: Arc<CircularBuffer<ControlEvent>> = Arc::new(CircularBuffer::new(256));
+    let midi_events: Arc<CircularBuffer<ControlEvent>> = Arc::new(CircularBuffer::new(256));
 
-    let mut amplitude = 0.0f32;
-    let target_amplitude = 0.5; // Desired amplitude
-    let ramp_speed = 0.01; // Speed of the ramp for smoothing
+    let recording: Arc<Mutex<Option<WavRecording>>> = Arc::new(Mutex::new(None));
+    let recording_clone = recording.clone();
 
-    let release_ramp_speed = target_amplitude / (sample_rate * RELEASE_TIME_SECONDS);
-
-    let mut releasing = false;
-
-    let mut next_value = move || {
-        let freq = *frequency.lock().unwrap();
-        if freq > 0.0 {
-            releasing = false;
-            if amplitude < target_amplitude {
-                amplitude += ramp_speed; // Ramp up the amplitude
-            }
-        } else {
-            if !releasing {
-                releasing = true;
-            }
-            if amplitude > 0.0 {
-                amplitude -= release_ramp_speed; // Ramp down the amplitude
-            }
-        }
-        sample_clock = (sample_clock + 1.0) % sample_rate;
-        (sample_clock * freq * 2.0 * std::f32::consts::PI / sample_rate).sin() * amplitude
-    };
+    let mut synth = Synth::new(
+        sample_rate,
+        channels,
+        recording.clone(),
+        key_events.clone(),
+        midi_events.clone(),
+    );
 
     let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
     let stream = device.build_output_stream(
         config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            write_data(data, channels, &mut next_value)
-        },
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| write_data(data, channels, &mut synth),
         err_fn,
         None, // Added Option<Duration>
     )?;
     stream.play()?;
 
+    // Keep the connection alive for the lifetime of `run`; dropping it
+    // closes the MIDI port.
+    let _midi_connection = start_midi_input(midi_events)?;
+
+    // Ask the terminal for per-key release events where supported so notes
+    // can be released individually instead of all at once via space.
+    let enhanced_keyboard = supports_keyboard_enhancement().unwrap_or(false);
+    if enhanced_keyboard {
+        execute!(
+            stdout(),
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        )?;
+    }
+
+    let mut held: HashSet<char> = HashSet::new();
+    let mut tuning_adsr = Adsr::default();
+    let mut osc_mode = OscillatorMode::Waveform(Waveform::Sine);
+
     loop {
-        if let Ok(Event::Key(KeyEvent { code, .. })) = read() {
+        if let Ok(Event::Key(KeyEvent { code, kind, .. })) = read() {
+            if kind == KeyEventKind::Release {
+                if let KeyCode::Char(c) = code {
+                    held.remove(&c);
+                    key_events.insert(ControlEvent::NoteOff {
+                        id: VoiceId::Key(c),
+                    });
+                }
+                continue;
+            }
+
             match code {
                 KeyCode::Char(' ') => {
-                    *frequency_clone.lock().unwrap() = 0.0;
+                    // Fallback "all notes off" for terminals without release events.
+                    for &c in &held {
+                        key_events.insert(ControlEvent::NoteOff {
+                            id: VoiceId::Key(c),
+                        });
+                    }
+                    held.clear();
+                    key_events.insert(ControlEvent::PitchBend(0.0));
                 }
                 KeyCode::Char('z') => {
                     if current_octave > 0 {
@@ -162,10 +753,71 @@ where
                         current_octave += 1;
                     }
                 }
+                // Live ADSR tuning: 1/2 attack, 3/4 decay, 5/6 sustain, 7/8 release.
+                KeyCode::Char('1') => adjust_adsr(&mut tuning_adsr, &key_events, |a| {
+                    a.attack = (a.attack - 0.02).max(0.001)
+                }),
+                KeyCode::Char('2') => {
+                    adjust_adsr(&mut tuning_adsr, &key_events, |a| a.attack += 0.02)
+                }
+                KeyCode::Char('3') => adjust_adsr(&mut tuning_adsr, &key_events, |a| {
+                    a.decay = (a.decay - 0.02).max(0.001)
+                }),
+                KeyCode::Char('4') => {
+                    adjust_adsr(&mut tuning_adsr, &key_events, |a| a.decay += 0.02)
+                }
+                KeyCode::Char('5') => adjust_adsr(&mut tuning_adsr, &key_events, |a| {
+                    a.sustain = (a.sustain - 0.05).max(0.0)
+                }),
+                KeyCode::Char('6') => adjust_adsr(&mut tuning_adsr, &key_events, |a| {
+                    a.sustain = (a.sustain + 0.05).min(1.0)
+                }),
+                KeyCode::Char('7') => adjust_adsr(&mut tuning_adsr, &key_events, |a| {
+                    a.release = (a.release - 0.02).max(0.001)
+                }),
+                KeyCode::Char('8') => {
+                    adjust_adsr(&mut tuning_adsr, &key_events, |a| a.release += 0.02)
+                }
+                KeyCode::Char('9') => {
+                    osc_mode = osc_mode.next();
+                    key_events.insert(ControlEvent::SetOscillator(osc_mode));
+                    eprintln!("oscillator: {:?}\r", osc_mode);
+                }
+                KeyCode::Char('r') => {
+                    let mut recording = recording_clone.lock().unwrap();
+                    match recording.take() {
+                        Some(finished) => {
+                            let path = default_wav_path();
+                            let result = write_wav_file(
+                                &path,
+                                &finished,
+                                sample_rate as u32,
+                                channels as u16,
+                            );
+                            match result {
+                                Ok(()) => eprintln!("wrote recording to {path}\r"),
+                                Err(err) => {
+                                    eprintln!("failed to write recording to {path}: {err}\r")
+                                }
+                            }
+                        }
+                        None => {
+                            *recording = Some(WavRecording::new());
+                            eprintln!("recording started\r");
+                        }
+                    }
+                }
                 KeyCode::Char(c) => {
                     if let Some(&(note, octave_offset)) = KEY_MAP.get(&c) {
-                        let freq = note_to_frequency(note, current_octave + octave_offset) as f32;
-                        *frequency_clone.lock().unwrap() = freq;
+                        if held.insert(c) {
+                            let freq =
+                                note_to_frequency(note, current_octave + octave_offset) as f32;
+                            key_events.insert(ControlEvent::NoteOn {
+                                id: VoiceId::Key(c),
+                                frequency: freq,
+                                velocity: 1.0,
+                            });
+                        }
                     }
                 }
                 KeyCode::Esc => break,
@@ -174,17 +826,217 @@ where
         }
     }
 
+    if let Some(finished) = recording_clone.lock().unwrap().take() {
+        let path = default_wav_path();
+        let result = write_wav_file(&path, &finished, sample_rate as u32, channels as u16);
+        match result {
+            Ok(()) => eprintln!("wrote recording to {path}\r"),
+            Err(err) => eprintln!("failed to write recording to {path}: {err}\r"),
+        }
+    }
+
+    if enhanced_keyboard {
+        execute!(stdout(), PopKeyboardEnhancementFlags)?;
+    }
+
     Ok(())
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
+fn adjust_adsr(adsr: &mut Adsr, events: &CircularBuffer<ControlEvent>, f: impl FnOnce(&mut Adsr)) {
+    f(adsr);
+    events.insert(ControlEvent::SetAdsr(*adsr));
+    eprintln!(
+        "attack={:.3}s decay={:.3}s sustain={:.2} release={:.3}s\r",
+        adsr.attack, adsr.decay, adsr.sustain, adsr.release
+    );
+}
+
+fn write_data<T>(output: &mut [T], channels: usize, synth: &mut Synth)
 where
     T: cpal::Sample + cpal::FromSample<f32>,
 {
+    // Apply everything queued since the last buffer fill up front, so the
+    // per-sample loop below never locks the control-plane state (only the
+    // recording Mutex is still locked per sample, in `next_sample`).
+    synth.drain_events();
+
     for frame in output.chunks_mut(channels) {
-        let value: T = T::from_sample(next_sample());
+        let value: T = T::from_sample(synth.next_sample());
         for sample in frame.iter_mut() {
             *sample = value;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn fifo_order_and_drop_when_full() {
+        // Capacity 4 means 3 usable slots: the ring always keeps one slot
+        // empty so `inp == out` unambiguously means "empty".
+        let buffer: CircularBuffer<i32> = CircularBuffer::new(4);
+
+        buffer.insert(1);
+        buffer.insert(2);
+        buffer.insert(3);
+        buffer.insert(4); // full; silently dropped
+
+        assert_eq!(buffer.read(), Some(1));
+        assert_eq!(buffer.read(), Some(2));
+        assert_eq!(buffer.read(), Some(3));
+        assert_eq!(buffer.read(), None);
+
+        buffer.insert(5);
+        assert_eq!(buffer.read(), Some(5));
+        assert_eq!(buffer.read(), None);
+    }
+
+    #[test]
+    fn concurrent_single_producer_single_consumer_round_trip() {
+        // One producer thread hammers `insert` while this thread drains
+        // with `read`, matching the real keyboard/MIDI-thread -> audio-
+        // callback hand-off. The producer retries on a full buffer so this
+        // test can assert every item arrives, not just that the ones which
+        // got through stayed in order.
+        const COUNT: usize = 100_000;
+        let buffer: Arc<CircularBuffer<usize>> = Arc::new(CircularBuffer::new(64));
+
+        let producer = {
+            let buffer = buffer.clone();
+            thread::spawn(move || {
+                for item in 0..COUNT {
+                    while !buffer.insert(item) {
+                        thread::yield_now();
+                    }
+                }
+            })
+        };
+
+        let mut received = Vec::with_capacity(COUNT);
+        while received.len() < COUNT {
+            if let Some(item) = buffer.read() {
+                received.push(item);
+            }
+        }
+
+        producer.join().unwrap();
+
+        // Every item must arrive exactly once, in FIFO order.
+        assert_eq!(received, (0..COUNT).collect::<Vec<_>>());
+    }
+}
+
+#[cfg(test)]
+mod midi_tests {
+    use super::*;
+
+    #[test]
+    fn note_on_with_velocity_scales_amplitude() {
+        match parse_midi_message(&[0x90, 60, 64]) {
+            Some(ControlEvent::NoteOn {
+                id,
+                frequency,
+                velocity,
+            }) => {
+                assert!(id == VoiceId::Midi(60));
+                assert!((velocity - 64.0 / 127.0).abs() < 1e-6);
+                assert!((frequency - midi_to_freq(60) as f32).abs() < 1e-3);
+            }
+            _ => panic!("expected NoteOn"),
+        }
+    }
+
+    #[test]
+    fn note_on_with_zero_velocity_is_note_off() {
+        match parse_midi_message(&[0x90, 60, 0]) {
+            Some(ControlEvent::NoteOff { id }) => assert!(id == VoiceId::Midi(60)),
+            _ => panic!("expected NoteOff"),
+        }
+    }
+
+    #[test]
+    fn note_off_status_byte() {
+        match parse_midi_message(&[0x80, 60, 0]) {
+            Some(ControlEvent::NoteOff { id }) => assert!(id == VoiceId::Midi(60)),
+            _ => panic!("expected NoteOff"),
+        }
+    }
+
+    #[test]
+    fn pitch_bend_centered_is_zero() {
+        // 0x2000 (lsb=0x00, msb=0x40) is the centered, no-bend value.
+        match parse_midi_message(&[0xE0, 0x00, 0x40]) {
+            Some(ControlEvent::PitchBend(semitones)) => assert!(semitones.abs() < 1e-3),
+            _ => panic!("expected PitchBend"),
+        }
+    }
+
+    #[test]
+    fn pitch_bend_extremes_are_plus_minus_two_semitones() {
+        match parse_midi_message(&[0xE0, 0x7F, 0x7F]) {
+            Some(ControlEvent::PitchBend(semitones)) => assert!((semitones - 2.0).abs() < 0.01),
+            _ => panic!("expected PitchBend"),
+        }
+        match parse_midi_message(&[0xE0, 0x00, 0x00]) {
+            Some(ControlEvent::PitchBend(semitones)) => assert!((semitones + 2.0).abs() < 0.01),
+            _ => panic!("expected PitchBend"),
+        }
+    }
+
+    #[test]
+    fn unknown_status_byte_is_ignored() {
+        // 0xB0 is a control-change message, which this synth doesn't handle.
+        assert!(parse_midi_message(&[0xB0, 7, 127]).is_none());
+    }
+
+    #[test]
+    fn truncated_messages_are_ignored() {
+        assert!(parse_midi_message(&[]).is_none());
+        assert!(parse_midi_message(&[0x90]).is_none());
+        assert!(parse_midi_message(&[0x90, 60]).is_none());
+        assert!(parse_midi_message(&[0xE0, 0x00]).is_none());
+    }
+}
+
+#[cfg(test)]
+mod wav_tests {
+    use super::*;
+
+    #[test]
+    fn header_layout_and_sample_count_round_trip() {
+        let mut recording = WavRecording::new();
+        recording.push(1.0);
+        recording.push(-1.0);
+        recording.push(0.0);
+
+        let path = std::env::temp_dir().join("my_synth_header_layout_and_sample_count.wav");
+        let path = path.to_str().unwrap();
+        write_wav_file(path, &recording, 44_100, 2).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let data_len = (recording.data.len() * 2) as u32;
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + data_len);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(&bytes[12..16], b"fmt ");
+        assert_eq!(u32::from_le_bytes(bytes[16..20].try_into().unwrap()), 16);
+        assert_eq!(u16::from_le_bytes(bytes[20..22].try_into().unwrap()), 1); // PCM
+        assert_eq!(u16::from_le_bytes(bytes[22..24].try_into().unwrap()), 2); // channels
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 44_100); // sample rate
+        let byte_rate = u32::from_le_bytes(bytes[28..32].try_into().unwrap());
+        assert_eq!(byte_rate, 44_100 * 2 * 2);
+        assert_eq!(u16::from_le_bytes(bytes[32..34].try_into().unwrap()), 4); // block align
+        assert_eq!(u16::from_le_bytes(bytes[34..36].try_into().unwrap()), 16); // bits per sample
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), data_len);
+
+        assert_eq!(bytes.len(), 44 + data_len as usize);
+        assert_eq!(i16::from_le_bytes(bytes[44..46].try_into().unwrap()), i16::MAX);
+        assert_eq!(i16::from_le_bytes(bytes[46..48].try_into().unwrap()), -i16::MAX);
+        assert_eq!(i16::from_le_bytes(bytes[48..50].try_into().unwrap()), 0);
+    }
+}